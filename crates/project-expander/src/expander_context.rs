@@ -0,0 +1,478 @@
+use crate::tasks_expander_error::TasksExpanderError;
+use miette::IntoDiagnostic;
+use moon_project::Project;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Signature of the closure used to query projects from the graph. It must be
+/// `Send + Sync` so a single context can be shared across the threads that
+/// expand tasks in parallel.
+pub type QueryFn<'graph, 'query> =
+    Box<dyn Fn(String) -> miette::Result<Vec<&'query Project>> + Send + Sync + 'graph>;
+
+/// Memoized project-query results, keyed by the query input string and shared
+/// across every task expanded with the same context. Guarded by a mutex so it
+/// can be consulted while tasks are expanded in parallel.
+pub type QueryCache<'query> = Mutex<FxHashMap<String, Vec<&'query Project>>>;
+
+pub struct ExpanderContext<'graph, 'query> {
+    pub check_ci_relationships: bool,
+
+    /// The project of the tasks being expanded.
+    pub project: &'graph Project,
+
+    /// Query the project graph for dependencies.
+    pub query: QueryFn<'graph, 'query>,
+
+    /// Absolute path to the workspace root.
+    pub workspace_root: &'graph Path,
+
+    /// Ordered chain of providers consulted to resolve `scheme://` env values.
+    /// Embedder-registered providers come first so they can claim additional
+    /// schemes such as `vault://` or `op://`.
+    pub env_providers: Vec<Arc<dyn EnvProvider>>,
+
+    /// Memoized project-query results, shared across every task expanded with
+    /// this context so repeated `project=`/`tag=` lookups hit memory.
+    pub query_cache: QueryCache<'query>,
+}
+
+impl<'graph, 'query> ExpanderContext<'graph, 'query> {
+    pub fn new(
+        project: &'graph Project,
+        workspace_root: &'graph Path,
+        query: QueryFn<'graph, 'query>,
+        check_ci_relationships: bool,
+    ) -> Self {
+        Self {
+            check_ci_relationships,
+            project,
+            query,
+            workspace_root,
+            env_providers: default_env_providers(workspace_root),
+            query_cache: Mutex::new(FxHashMap::default()),
+        }
+    }
+
+    /// Register an additional env-value provider. Registered providers take
+    /// precedence over the built-ins, letting an embedder claim schemes like
+    /// `vault://` or `op://`.
+    pub fn register_env_provider(&mut self, provider: Arc<dyn EnvProvider>) {
+        self.env_providers.insert(0, provider);
+    }
+
+    /// Resolve a single env value against the provider chain. The value is only
+    /// treated as a reference when it begins with a real URI scheme token
+    /// (`scheme://…`) *and* some registered provider claims that scheme;
+    /// anything else — a plain value, an ordinary URL like
+    /// `--endpoint=https://…`, or a `scheme://` no provider owns — is returned
+    /// as `None` so the literal is preserved. Once a provider claims the
+    /// scheme, failing to resolve it is an error, so a configured-but-broken
+    /// integration fails loudly instead of leaking the reference into the env.
+    pub(crate) fn resolve_env_value(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> miette::Result<Option<String>> {
+        let Some((scheme, path)) = value.split_once("://") else {
+            return Ok(None);
+        };
+
+        if !is_uri_scheme(scheme) {
+            return Ok(None);
+        }
+
+        let Some(provider) = self
+            .env_providers
+            .iter()
+            .find(|provider| provider.scheme() == scheme)
+        else {
+            return Ok(None);
+        };
+
+        match provider.resolve(path)? {
+            Some(resolved) => Ok(Some(resolved)),
+            None => Err(TasksExpanderError::UnresolvedEnvProvider {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Whether `scheme` is a syntactically valid URI scheme token
+/// (`^[A-Za-z][A-Za-z0-9+.-]*$`). Used to tell a provider reference like
+/// `vault://secret` apart from a value that merely contains `://`, such as
+/// `--endpoint=https://api.example.com`.
+fn is_uri_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() => {}
+        _ => return false,
+    }
+
+    chars.all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '+' | '-' | '.'))
+}
+
+/// The built-in provider chain: a file reader and a process-env passthrough.
+fn default_env_providers(workspace_root: &Path) -> Vec<Arc<dyn EnvProvider>> {
+    vec![
+        Arc::new(FileEnvProvider {
+            workspace_root: workspace_root.to_path_buf(),
+        }),
+        Arc::new(ProcessEnvProvider),
+    ]
+}
+
+/// Resolves an environment value from an external source such as a secrets
+/// store or a file on disk. A value written as `scheme://path` in `task.env`
+/// (or a loaded `.env` file) is dispatched to the first provider whose
+/// [`EnvProvider::scheme`] matches; the returned value replaces the reference.
+pub trait EnvProvider: Send + Sync {
+    /// The URI scheme this provider claims, e.g. `file`, `env`, `vault`.
+    fn scheme(&self) -> &str;
+
+    /// Resolve the reference `key` (the portion following `scheme://`) to its
+    /// value, or `None` when the reference cannot be satisfied.
+    fn resolve(&self, key: &str) -> miette::Result<Option<String>>;
+}
+
+/// Reads a value from a file referenced relative to the workspace root.
+struct FileEnvProvider {
+    workspace_root: PathBuf,
+}
+
+impl EnvProvider for FileEnvProvider {
+    fn scheme(&self) -> &str {
+        "file"
+    }
+
+    fn resolve(&self, key: &str) -> miette::Result<Option<String>> {
+        let path = self.workspace_root.join(key);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        // Trim the trailing newline so a one-line secret file behaves like an
+        // inline value
+        Ok(Some(
+            fs::read_to_string(&path)
+                .into_diagnostic()?
+                .trim_end()
+                .to_owned(),
+        ))
+    }
+}
+
+/// Passes a reference through to the host process environment.
+struct ProcessEnvProvider;
+
+impl EnvProvider for ProcessEnvProvider {
+    fn scheme(&self) -> &str {
+        "env"
+    }
+
+    fn resolve(&self, key: &str) -> miette::Result<Option<String>> {
+        Ok(std::env::var(key).ok())
+    }
+}
+
+/// Substitute `${VAR}` / `$VAR` references in `value` using `env_map`.
+///
+/// Undefined references resolve to an empty string. An escaped `\${...}` (or
+/// `\$VAR`) is preserved literally with the backslash dropped. A reference to
+/// `base_name` — the key currently being defined — resolves against its
+/// pre-existing value in `env_map` and is never re-expanded, so self-
+/// referential and cyclic definitions terminate.
+pub fn substitute_env_var(base_name: &str, value: &str, env_map: &FxHashMap<String, String>) -> String {
+    if !value.contains('$') {
+        return value.to_owned();
+    }
+
+    let bytes = value.as_bytes();
+    let mut result = String::with_capacity(value.len());
+    let mut i = 0;
+
+    while i < value.len() {
+        let byte = bytes[i];
+
+        // Escaped `\$...` is emitted verbatim (without the backslash) and not
+        // expanded
+        if byte == b'\\' && i + 1 < value.len() && bytes[i + 1] == b'$' {
+            result.push('$');
+            i += 2;
+            i = copy_var_token(value, i, &mut result);
+            continue;
+        }
+
+        if byte == b'$' {
+            if let Some((name, next)) = parse_var(value, i) {
+                let replacement = if name == base_name {
+                    env_map.get(base_name)
+                } else {
+                    env_map.get(name)
+                };
+
+                result.push_str(replacement.map(String::as_str).unwrap_or_default());
+                i = next;
+                continue;
+            }
+        }
+
+        let ch = value[i..].chars().next().unwrap();
+        result.push(ch);
+        i += ch.len_utf8();
+    }
+
+    result
+}
+
+/// Run [`substitute_env_var`] over every value in the map, using each entry's
+/// own key as the base name.
+pub fn substitute_env_vars(env: FxHashMap<String, String>) -> FxHashMap<String, String> {
+    substitute_env_vars_except(env, &FxHashSet::default())
+}
+
+/// Like [`substitute_env_vars`], but leaves the values of `skip` keys verbatim.
+/// Used for `.env` file values, which are interpolated once in declaration
+/// order before being merged; running the final pass over them again would
+/// strip the backslash from an escaped `\${...}` and then expand it.
+pub fn substitute_env_vars_except(
+    env: FxHashMap<String, String>,
+    skip: &FxHashSet<String>,
+) -> FxHashMap<String, String> {
+    let mut result = FxHashMap::default();
+
+    for (key, value) in &env {
+        let value = if skip.contains(key) {
+            value.to_owned()
+        } else {
+            substitute_env_var(key, value, &env)
+        };
+
+        result.insert(key.to_owned(), value);
+    }
+
+    result
+}
+
+/// Parse a `${NAME}` or `$NAME` reference starting at `start` (the `$`),
+/// returning the variable name and the index just past the reference.
+fn parse_var(value: &str, start: usize) -> Option<(&str, usize)> {
+    let bytes = value.as_bytes();
+    let mut i = start + 1;
+
+    if i < value.len() && bytes[i] == b'{' {
+        i += 1;
+        let name_start = i;
+
+        while i < value.len() && bytes[i] != b'}' {
+            i += 1;
+        }
+
+        if i >= value.len() || i == name_start {
+            return None;
+        }
+
+        Some((&value[name_start..i], i + 1))
+    } else {
+        let name_start = i;
+
+        while i < value.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+            i += 1;
+        }
+
+        if i == name_start {
+            None
+        } else {
+            Some((&value[name_start..i], i))
+        }
+    }
+}
+
+/// Copy a `${...}` or `$NAME` token starting at `i` verbatim into `result`,
+/// returning the index just past it. Used to preserve escaped references.
+fn copy_var_token(value: &str, mut i: usize, result: &mut String) -> usize {
+    let bytes = value.as_bytes();
+
+    if i < value.len() && bytes[i] == b'{' {
+        result.push('{');
+        i += 1;
+
+        while i < value.len() && bytes[i] != b'}' {
+            result.push(bytes[i] as char);
+            i += 1;
+        }
+
+        if i < value.len() {
+            result.push('}');
+            i += 1;
+        }
+    } else {
+        while i < value.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+            result.push(bytes[i] as char);
+            i += 1;
+        }
+    }
+
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(pairs: &[(&str, &str)]) -> FxHashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    mod substitution {
+        use super::*;
+
+        #[test]
+        fn expands_braced_and_bare_references() {
+            let env = map(&[("USER", "moon"), ("HOST", "local")]);
+
+            assert_eq!(
+                substitute_env_var("", "${USER}@$HOST", &env),
+                "moon@local"
+            );
+        }
+
+        #[test]
+        fn undefined_resolves_to_empty() {
+            let env = map(&[]);
+
+            assert_eq!(substitute_env_var("", "a${MISSING}b", &env), "ab");
+        }
+
+        #[test]
+        fn preserves_escaped_reference() {
+            let env = map(&[("USER", "moon")]);
+
+            assert_eq!(
+                substitute_env_var("", "\\${USER} = ${USER}", &env),
+                "${USER} = moon"
+            );
+        }
+
+        #[test]
+        fn self_reference_uses_preexisting_value() {
+            let env = map(&[("PATH", "/usr/bin")]);
+
+            // Defining PATH in terms of itself resolves against the prior value
+            // rather than looping.
+            assert_eq!(
+                substitute_env_var("PATH", "${PATH}:/opt/bin", &env),
+                "/usr/bin:/opt/bin"
+            );
+        }
+
+        #[test]
+        fn substitute_env_vars_uses_key_as_base() {
+            let result = substitute_env_vars(map(&[("GREETING", "hi $NAME"), ("NAME", "moon")]));
+
+            assert_eq!(result.get("GREETING").unwrap(), "hi moon");
+        }
+    }
+
+    mod providers {
+        use super::*;
+
+        fn providers(root: &Path) -> Vec<Arc<dyn EnvProvider>> {
+            default_env_providers(root)
+        }
+
+        fn context<'a>(root: &'a Path, project: &'a Project) -> ExpanderContext<'a, 'a> {
+            ExpanderContext {
+                check_ci_relationships: false,
+                project,
+                query: Box::new(|_| Ok(vec![])),
+                workspace_root: root,
+                env_providers: providers(root),
+                query_cache: Mutex::new(FxHashMap::default()),
+            }
+        }
+
+        #[test]
+        fn leaves_plain_urls_alone() {
+            let root = std::env::temp_dir();
+            let project = Project::default();
+            let ctx = context(&root, &project);
+
+            assert_eq!(
+                ctx.resolve_env_value("DATABASE_URL", "postgres://db/app")
+                    .unwrap(),
+                None
+            );
+        }
+
+        #[test]
+        fn passes_through_unclaimed_provider_scheme() {
+            let root = std::env::temp_dir();
+            let project = Project::default();
+            let ctx = context(&root, &project);
+
+            // No provider claims `vault`, so the reference is left untouched for
+            // an embedder that registered one — or for the literal to surface.
+            assert_eq!(
+                ctx.resolve_env_value("SECRET", "vault://app/token").unwrap(),
+                None
+            );
+        }
+
+        #[test]
+        fn ignores_embedded_url_that_is_not_a_reference() {
+            let root = std::env::temp_dir();
+            let project = Project::default();
+            let ctx = context(&root, &project);
+
+            // The text before `://` is not a bare scheme token, so the value is
+            // a plain literal rather than a provider reference.
+            assert_eq!(
+                ctx.resolve_env_value("API", "--endpoint=https://api.example.com")
+                    .unwrap(),
+                None
+            );
+        }
+
+        #[test]
+        fn errors_when_claimed_provider_cannot_resolve() {
+            let root = std::env::temp_dir();
+            let project = Project::default();
+            let ctx = context(&root, &project);
+
+            // `file` is claimed by the built-in provider, so a missing target is
+            // a hard error rather than a silently leaked reference.
+            assert!(ctx
+                .resolve_env_value("TOKEN", "file://does-not-exist.txt")
+                .is_err());
+        }
+
+        #[test]
+        fn reads_a_file_relative_to_the_workspace() {
+            let root = std::env::temp_dir();
+            let path = root.join("moon-env-provider-test.txt");
+            fs::write(&path, "s3cret\n").unwrap();
+
+            let project = Project::default();
+            let ctx = context(&root, &project);
+
+            assert_eq!(
+                ctx.resolve_env_value("TOKEN", "file://moon-env-provider-test.txt")
+                    .unwrap(),
+                Some("s3cret".into())
+            );
+
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use miette::Diagnostic;
+use moon_task::Target;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Error, Debug, Diagnostic)]
+pub enum TasksExpanderError {
+    #[diagnostic(code(task_expander::invalid_env_file))]
+    #[error("Failed to parse env file {}.", .path.display())]
+    InvalidEnvFile {
+        path: PathBuf,
+        #[source]
+        error: Box<dotenvy::Error>,
+    },
+
+    #[diagnostic(code(task_expander::unresolved_env_provider))]
+    #[error("No registered provider could resolve env variable {key} from value {value}.")]
+    UnresolvedEnvProvider { key: String, value: String },
+
+    #[diagnostic(code(task_expander::unknown_target))]
+    #[error("Invalid dependency {dep} for {task}, target does not exist.")]
+    UnknownTarget { dep: Target, task: Target },
+
+    #[diagnostic(code(task_expander::allow_failure_requirement))]
+    #[error(
+        "Task {task} cannot depend on task {dep} as it is allowed to fail, which may cause unexpected errors."
+    )]
+    AllowFailureDepRequirement { dep: Target, task: Target },
+
+    #[diagnostic(code(task_expander::run_in_ci_requirement))]
+    #[error(
+        "Task {task} cannot depend on task {dep} as it will not run in CI, while the parent is set to run."
+    )]
+    RunInCiDepRequirement { dep: Target, task: Target },
+
+    #[diagnostic(code(task_expander::persistent_requirement))]
+    #[error("Non-persistent task {task} cannot depend on persistent task {dep}.")]
+    PersistentDepRequirement { dep: Target, task: Target },
+
+    #[diagnostic(code(task_expander::unsupported_scope_in_deps))]
+    #[error("Dependency {dep} for task {task} uses an unsupported target scope.")]
+    UnsupportedTargetScopeInDeps { dep: Target, task: Target },
+}